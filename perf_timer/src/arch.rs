@@ -1,4 +1,7 @@
-use core::{sync::atomic::AtomicU64, u64};
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    u64,
+};
 
 #[cfg(target_arch = "x86_64")]
 pub use x64::X64 as Arch;
@@ -9,12 +12,29 @@ pub use aarch64::Aarch64 as Arch;
 // QEMU uses the ACPI frequency when CPUID-based frequency determination is not available.
 const QEMU_DEFAULT_FREQUENCY: u64 = 3579545;
 
+// Number of short samples taken during runtime calibration; the median rejects outliers.
+const CALIBRATION_SAMPLES: usize = 5;
+
 //
 static CPU_FREQUENCY: AtomicU64 = AtomicU64::new(0);
 
+/// Package, core, and thread identifiers for a logical processor, used to attribute
+/// performance-counter reads and per-core calibration to a specific core.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuTopology {
+    /// Physical package (socket) identifier.
+    pub package: u32,
+    /// Core identifier within the package.
+    pub core: u32,
+    /// Hardware thread (SMT) identifier within the core.
+    pub thread: u32,
+}
+
 pub trait ArchFunctionality {
     /// Value of the counter.
     fn cpu_count() -> u64;
+    /// Package/core/thread identifiers of the processor the call runs on.
+    fn cpu_topology() -> CpuTopology;
     /// Value in Hz of how often the counter increment.
     fn cpu_count_frequency() -> u64;
     /// Value the performance counter starts with when it rolls over.
@@ -25,6 +45,76 @@ pub trait ArchFunctionality {
     fn cpu_count_end() -> u64 {
         u64::MAX
     }
+
+    /// Whether the performance counter reads consistently across all logical processors, so
+    /// elapsed-time math is valid regardless of which core performed the read. Architectures with
+    /// a system-wide counter report `true` unconditionally.
+    fn counter_is_synchronized() -> bool {
+        true
+    }
+
+    /// Elapsed ticks between two counter reads, correct across a single wraparound. When `end`
+    /// trails `start` the counter is assumed to have rolled over once, so the span wrapping
+    /// through [`cpu_count_end`](Self::cpu_count_end)/[`cpu_count_start`](Self::cpu_count_start)
+    /// is summed instead.
+    fn cpu_count_elapsed(start: u64, end: u64) -> u64 {
+        if end >= start {
+            end - start
+        } else {
+            (Self::cpu_count_end() - start) + (end - Self::cpu_count_start()) + 1
+        }
+    }
+
+    /// Converts counter ticks to nanoseconds using the cached
+    /// [`cpu_count_frequency`](Self::cpu_count_frequency), with 128-bit intermediate math to
+    /// avoid overflow on large tick counts.
+    fn ticks_to_nanos(ticks: u64) -> u64 {
+        let frequency = Self::cpu_count_frequency();
+        if frequency == 0 {
+            return 0;
+        }
+        (ticks as u128 * 1_000_000_000 / frequency as u128) as u64
+    }
+
+    /// Measures the counter rate empirically against a caller-supplied monotonic reference.
+    ///
+    /// Use this when neither CPUID nor an architectural frequency register yields a usable
+    /// rate. `reference_hz` is the tick rate of the reference clock and `reference_read` returns
+    /// its current value (e.g. the ACPI PM timer, or a `Stall`-driven microsecond counter). The
+    /// counter and the reference are sampled at both ends of a fixed interval and the rate is
+    /// `(tsc_end - tsc_start) * reference_hz / (ref_end - ref_start)`. Several short samples are
+    /// taken and the median is used so a single descheduled read cannot skew the result. The
+    /// calibrated value is cached in `CPU_FREQUENCY` exactly like the CPUID path.
+    fn calibrate_frequency(reference_hz: u64, reference_read: impl Fn() -> u64) -> u64 {
+        // Sample over roughly a millisecond of reference time per round.
+        let interval = (reference_hz / 1000).max(1);
+
+        let mut samples = [0u64; CALIBRATION_SAMPLES];
+        for sample in samples.iter_mut() {
+            let ref_start = reference_read();
+            let tsc_start = Self::cpu_count();
+
+            // Busy-wait until the reference timer has advanced by the fixed interval.
+            let mut ref_end = reference_read();
+            while ref_end.wrapping_sub(ref_start) < interval {
+                ref_end = reference_read();
+            }
+            let tsc_end = Self::cpu_count();
+
+            let tsc_delta = Self::cpu_count_elapsed(tsc_start, tsc_end);
+            let ref_delta = ref_end.wrapping_sub(ref_start);
+
+            *sample = (tsc_delta as u128 * reference_hz as u128 / ref_delta as u128) as u64;
+        }
+
+        samples.sort_unstable();
+        let frequency = samples[CALIBRATION_SAMPLES / 2];
+
+        CPU_FREQUENCY.store(frequency, Ordering::Relaxed);
+        log::info!("CPU frequency calibrated to {} Hz", frequency);
+
+        frequency
+    }
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -52,10 +142,49 @@ pub(crate) mod x64 {
             unsafe { x86_64::_rdtsc() }
         }
 
+        fn counter_is_synchronized() -> bool {
+            // Per-core TSCs only read consistently once `sync_counters` has aligned them; report
+            // the actual synchronization state rather than mere `IA32_TSC_ADJUST` capability.
+            crate::counter_sync::is_synchronized()
+        }
+
+        fn cpu_topology() -> CpuTopology {
+            // Extended topology enumeration: leaf 0x0B (leaf 0x1F is a superset with the same
+            // layout). Each subleaf describes one hierarchy level; the per-level shift widths are
+            // applied to the x2APIC ID to extract the identifiers.
+            let mut smt_shift = 0u32;
+            let mut core_shift = 0u32;
+            let mut apic_id = 0u32;
+            let mut level = 0u32;
+            loop {
+                let leaf = unsafe { x86_64::__cpuid_count(0x0B, level) };
+                // ecx[15:8] is the level type; 0 marks the end of the valid levels.
+                let level_type = (leaf.ecx >> 8) & 0xFF;
+                if level_type == 0 {
+                    break;
+                }
+                apic_id = leaf.edx; // x2APIC ID of the current logical processor.
+                let shift = leaf.eax & 0x1F; // bits to shift the APIC ID past this level.
+                match level_type {
+                    1 => smt_shift = shift,  // SMT (thread) level.
+                    2 => core_shift = shift, // Core level.
+                    _ => {}
+                }
+                level += 1;
+            }
+
+            let thread = apic_id & ((1 << smt_shift) - 1);
+            let core = (apic_id >> smt_shift) & ((1 << core_shift.saturating_sub(smt_shift)) - 1);
+            let package = apic_id >> core_shift;
+
+            CpuTopology { package, core, thread }
+        }
+
         fn cpu_count_frequency() -> u64 {
-            let cpuid = unsafe { core::arch::x86_64::__cpuid(0x16) };
-            if cpuid.eax != 0 {
-                log::info!("CPU frequency from leaf 0x16: {} MHz", cpuid.eax as u64 * 1_000_000);
+            // Leaf 0x16 eax is the processor base frequency in MHz.
+            let base_mhz = unsafe { x86_64::__cpuid(0x16) }.eax;
+            if base_mhz != 0 {
+                log::info!("CPU frequency from leaf 0x16: {} MHz", base_mhz);
             }
 
             let cached = CPU_FREQUENCY.load(Ordering::Relaxed);
@@ -71,21 +200,144 @@ pub(crate) mod x64 {
                 ..
             } = unsafe { x86_64::__cpuid(0x15) };
 
-            let frequency = if ecx == 0 {
-                #[cfg(feature = "validate_cpu_features")]
-                log::warn!("CPU does not support CPUID-based frequency determination");
-
-                QEMU_DEFAULT_FREQUENCY
+            let frequency = if ecx != 0 && eax != 0 && ebx != 0 {
+                // Crystal clock is enumerated directly; scale it by the TSC/crystal ratio.
+                (ecx as u64 * ebx as u64) / eax as u64
+            } else if base_mhz != 0 {
+                // Leaf 0x15 lacks the crystal frequency (or the ratio), but leaf 0x16 reports the
+                // base frequency directly: reconstructing the crystal and reapplying the ratio
+                // reduces exactly to the base frequency in Hz, so use it straight.
+                base_mhz as u64 * 1_000_000
             } else {
-                (ecx * (ebx / eax)) as u64
+                Self::fallback_frequency()
             };
 
             CPU_FREQUENCY.store(frequency, Ordering::Relaxed);
-            log::info!("CPU frequency from leaf 0x15 {}", frequency);
+            log::info!("CPU frequency {} Hz", frequency);
 
             frequency
         }
     }
+
+    impl X64 {
+        /// Last-resort frequency source used when CPUID leaves 0x15/0x16 are unavailable.
+        ///
+        /// Pre-Skylake Atom parts (Silvermont, Airmont, ...) report neither leaf, but encode a
+        /// bus ratio in the platform-info MSR that, combined with the family/model-derived bus
+        /// reference clock, yields the TSC rate. That path requires ring-0 MSR access and so sits
+        /// behind the `msr_frequency` feature; otherwise we fall back to the QEMU default.
+        fn fallback_frequency() -> u64 {
+            #[cfg(feature = "msr_frequency")]
+            if let Some(frequency) = Self::frequency_from_msr() {
+                log::info!("CPU frequency from MSR 0x{:X}: {} Hz", MSR_PLATFORM_INFO, frequency);
+                return frequency;
+            }
+
+            #[cfg(feature = "validate_cpu_features")]
+            log::warn!("CPU does not support CPUID-based frequency determination");
+
+            QEMU_DEFAULT_FREQUENCY
+        }
+
+        /// Derives the frequency from the platform-info MSR bus ratio times the bus reference
+        /// clock selected from the CPU family/model. Returns `None` on unknown parts.
+        #[cfg(feature = "msr_frequency")]
+        fn frequency_from_msr() -> Option<u64> {
+            let bus_freq = atom_bus_frequency(unsafe { x86_64::__cpuid(0x01) }.eax)?;
+
+            // MSR_PLATFORM_INFO bits [15:8] hold the maximum non-turbo bus ratio.
+            let ratio = (unsafe { read_msr(MSR_PLATFORM_INFO) } >> 8) & 0xFF;
+            if ratio == 0 {
+                return None;
+            }
+
+            Some(bus_freq * ratio)
+        }
+    }
+
+    /// Platform information MSR; bits [15:8] are the maximum non-turbo ratio.
+    #[cfg(feature = "msr_frequency")]
+    const MSR_PLATFORM_INFO: u32 = 0xCE;
+
+    /// Reads a model-specific register. Requires ring-0 privilege.
+    #[cfg(feature = "msr_frequency")]
+    unsafe fn read_msr(msr: u32) -> u64 {
+        let (high, low): (u32, u32);
+        core::arch::asm!(
+            "rdmsr",
+            in("ecx") msr,
+            out("eax") low,
+            out("edx") high,
+            options(nomem, nostack, preserves_flags),
+        );
+        ((high as u64) << 32) | low as u64
+    }
+
+    /// Decodes the effective family and model from the leaf 0x01 `eax` signature, folding in the
+    /// extended family/model fields as the architecture specifies.
+    #[cfg(feature = "msr_frequency")]
+    pub(crate) fn decode_family_model(signature: u32) -> (u32, u32) {
+        let base_family = (signature >> 8) & 0xF;
+        let base_model = (signature >> 4) & 0xF;
+        let family = if base_family == 0xF {
+            base_family + ((signature >> 20) & 0xFF)
+        } else {
+            base_family
+        };
+        let model = if base_family == 0x6 || base_family == 0xF {
+            base_model + (((signature >> 16) & 0xF) << 4)
+        } else {
+            base_model
+        };
+        (family, model)
+    }
+
+    /// Bus reference clock in Hz for Silvermont/Airmont-class Atom cores, or `None` when the
+    /// family/model has no canned reference clock. Kept as a pure function of the CPUID
+    /// signature so it can be exercised with known inputs.
+    #[cfg(feature = "msr_frequency")]
+    pub(crate) fn atom_bus_frequency(signature: u32) -> Option<u64> {
+        let (family, model) = decode_family_model(signature);
+        if family != 0x6 {
+            return None;
+        }
+        let freq = match model {
+            0x37 | 0x5D => 83_200_000,  // Silvermont (Bay Trail, SoFIA)
+            0x4A | 0x5A => 100_000_000, // Silvermont (Merrifield, Moorefield)
+            0x4D => 100_000_000,        // Silvermont (Avoton, Rangeley)
+            0x4C => 133_330_000,        // Airmont (Cherry Trail, Braswell)
+            0x5C => 166_700_000,        // Airmont (Apollo Lake, Denverton)
+            _ => return None,
+        };
+        Some(freq)
+    }
+
+    #[cfg(all(test, feature = "msr_frequency"))]
+    mod tests {
+        use super::{atom_bus_frequency, decode_family_model};
+
+        #[test]
+        fn decodes_extended_family_model() {
+            // Silvermont (model 0x37): base family 0x6, base model 0x7, extended model 0x3.
+            assert_eq!(decode_family_model(0x00030670), (0x6, 0x37));
+            // Airmont (model 0x4C): extended model folds into the high nibble.
+            assert_eq!(decode_family_model(0x000406C0), (0x6, 0x4C));
+        }
+
+        #[test]
+        fn selects_atom_bus_frequency() {
+            assert_eq!(atom_bus_frequency(0x00030670), Some(83_200_000)); // Silvermont 0x37
+            assert_eq!(atom_bus_frequency(0x000406D0), Some(100_000_000)); // Silvermont 0x4D
+            assert_eq!(atom_bus_frequency(0x000406C0), Some(133_330_000)); // Airmont 0x4C
+            assert_eq!(atom_bus_frequency(0x000506C0), Some(166_700_000)); // Airmont 0x5C
+        }
+
+        #[test]
+        fn unknown_model_has_no_reference_clock() {
+            // Family 0x6 but not an Atom model we have a canned clock for.
+            assert_eq!(atom_bus_frequency(0x000206A0), None);
+        }
+    }
 }
 
 #[cfg(target_arch = "aarch64")]
@@ -101,5 +353,29 @@ pub(crate) mod aarch64 {
         fn cpu_count_frequency() -> u64 {
             registers::CNTFRQ_EL0.get()
         }
+
+        fn cpu_topology() -> CpuTopology {
+            let mpidr = registers::MPIDR_EL1.get();
+
+            // Uniprocessor systems set the U bit; there is a single CPU to attribute to.
+            const MPIDR_UP_BITMASK: u64 = 1 << 30;
+            if mpidr & MPIDR_UP_BITMASK != 0 {
+                return CpuTopology { package: 0, core: 0, thread: 0 };
+            }
+
+            // Affinity levels Aff0..Aff3. Note the GIC caps Aff0 at the implemented CPU count, so
+            // raw affinity is not a reliable *absolute* core ID on large systems; callers should
+            // treat these as a hierarchical label rather than a dense index.
+            let aff0 = (mpidr & 0xFF) as u32;
+            let aff1 = ((mpidr >> 8) & 0xFF) as u32;
+            let aff2 = ((mpidr >> 16) & 0xFF) as u32;
+            let aff3 = ((mpidr >> 32) & 0xFF) as u32;
+
+            CpuTopology {
+                package: (aff3 << 8) | aff2,
+                core: aff1,
+                thread: aff0,
+            }
+        }
     }
 }
@@ -0,0 +1,147 @@
+//! Cross-core synchronization of the architectural performance counter.
+//!
+//! Elapsed-time math built on [`ArchFunctionality::cpu_count`](crate::arch::ArchFunctionality::cpu_count)
+//! is only meaningful if every logical processor observes the same counter value at a given
+//! instant. On x86_64 the TSC is per-core and can power up at arbitrary offsets, so this module
+//! realigns each AP's `rdtsc` through `IA32_TSC_ADJUST`. On aarch64 `CNTPCT_EL0` is already
+//! system-wide, so synchronization is a no-op.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// MSR index of `IA32_TSC_ADJUST`. Writing to it offsets subsequent `rdtsc` reads by the stored
+/// value, letting an AP align its counter to a lead CPU without touching the raw hardware TSC.
+#[cfg(target_arch = "x86_64")]
+pub const IA32_TSC_ADJUST: u32 = 0x3B;
+
+/// Target counter value published by the lead CPU for the APs to converge on.
+static TARGET_TSC: AtomicU64 = AtomicU64::new(0);
+
+/// Set once [`sync_counters`] has completed an alignment pass across the supplied processors.
+static SYNCHRONIZED: AtomicBool = AtomicBool::new(false);
+
+/// Whether [`sync_counters`] has aligned the performance counter across the logical processors.
+pub fn is_synchronized() -> bool {
+    SYNCHRONIZED.load(Ordering::SeqCst)
+}
+
+/// Ring-0 access a single processor needs to realign its counter.
+///
+/// The algorithm is expressed against this trait rather than raw `rdtsc`/`wrmsr` so the
+/// barrier/atomic logic can be driven with a mock backend off-target.
+pub trait TscAdjust {
+    /// Whether this processor supports `IA32_TSC_ADJUST` (CPUID leaf 7 ebx bit 1).
+    fn supports_tsc_adjust(&self) -> bool;
+    /// Current counter value as observed through any active adjust offset.
+    fn read_count(&self) -> u64;
+    /// Writes `IA32_TSC_ADJUST`, shifting future reads so the counter matches a target.
+    fn write_adjust(&mut self, value: u64);
+}
+
+/// Realigns a single processor's counter to `target`, clearing any pre-existing adjust offset
+/// first so the read reflects the raw counter. Returns the adjust value written.
+fn align_to(cpu: &mut impl TscAdjust, target: u64) -> u64 {
+    cpu.write_adjust(0);
+    let raw = cpu.read_count();
+    let adjust = target.wrapping_sub(raw);
+    cpu.write_adjust(adjust);
+    adjust
+}
+
+/// Synchronizes the performance counter across the supplied logical processors.
+///
+/// The first CPU yielded is treated as the lead: it zeroes its own adjust offset and publishes
+/// the resulting raw counter value as the target. Every remaining CPU (AP) then writes
+/// `IA32_TSC_ADJUST` so its `rdtsc` matches that target. The APs are aligned one after another in
+/// the order the iterator yields them; there is no barrier across processors. APs that do not
+/// support `IA32_TSC_ADJUST` are skipped. If the lead does not support it, nothing is written and
+/// the counter is not marked synchronized. On a single-CPU iterator this reduces to zeroing the
+/// lead's offset, publishing the target and marking the counter synchronized.
+pub fn sync_counters<C: TscAdjust>(mut cpus: impl Iterator<Item = C>) {
+    let Some(mut lead) = cpus.next() else {
+        return;
+    };
+    if !lead.supports_tsc_adjust() {
+        return;
+    }
+
+    lead.write_adjust(0);
+    TARGET_TSC.store(lead.read_count(), Ordering::SeqCst);
+
+    for mut ap in cpus {
+        if !ap.supports_tsc_adjust() {
+            continue;
+        }
+        align_to(&mut ap, TARGET_TSC.load(Ordering::SeqCst));
+    }
+
+    SYNCHRONIZED.store(true, Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::Cell, rc::Rc};
+
+    /// Mock backend whose adjust offset is shared through an `Rc<Cell>`, so writes performed on a
+    /// copy handed to `sync_counters` remain observable through the handle kept by the test.
+    #[derive(Clone)]
+    struct MockCpu {
+        raw: u64,
+        adjust: Rc<Cell<u64>>,
+        supported: bool,
+    }
+
+    impl MockCpu {
+        fn new(raw: u64, supported: bool) -> Self {
+            Self { raw, adjust: Rc::new(Cell::new(0)), supported }
+        }
+    }
+
+    impl TscAdjust for MockCpu {
+        fn supports_tsc_adjust(&self) -> bool {
+            self.supported
+        }
+        fn read_count(&self) -> u64 {
+            self.raw.wrapping_add(self.adjust.get())
+        }
+        fn write_adjust(&mut self, value: u64) {
+            self.adjust.set(value);
+        }
+    }
+
+    #[test]
+    fn align_to_lands_on_target() {
+        let mut cpu = MockCpu::new(100, true);
+        cpu.write_adjust(9_999); // stale offset that must be cleared first.
+
+        let adjust = align_to(&mut cpu, 5_000);
+
+        assert_eq!(cpu.read_count(), 5_000);
+        assert_eq!(adjust, 5_000u64.wrapping_sub(100));
+    }
+
+    #[test]
+    fn sync_counters_barrier_logic() {
+        // Empty and unsupported-lead iterators are no-ops and leave the counter unsynchronized.
+        sync_counters(core::iter::empty::<MockCpu>());
+        assert!(!is_synchronized());
+        sync_counters(IntoIterator::into_iter([MockCpu::new(1, false)]));
+        assert!(!is_synchronized());
+
+        // Lead publishes its (offset-cleared) counter; the supported AP aligns to it and the
+        // unsupported CPU is skipped.
+        let lead = MockCpu::new(1_000, true);
+        let ap = MockCpu::new(50, true);
+        let unsupported = MockCpu::new(7, false);
+        sync_counters(IntoIterator::into_iter([lead.clone(), ap.clone(), unsupported.clone()]));
+
+        assert_eq!(lead.read_count(), 1_000);
+        assert_eq!(ap.read_count(), 1_000);
+        assert_eq!(unsupported.read_count(), 7);
+        assert!(is_synchronized());
+
+        // A single-CPU iterator still completes a (trivial) synchronization pass.
+        sync_counters(IntoIterator::into_iter([MockCpu::new(42, true)]));
+        assert!(is_synchronized());
+    }
+}
@@ -0,0 +1,7 @@
+#![no_std]
+
+#[cfg(test)]
+extern crate std;
+
+pub mod arch;
+pub mod counter_sync;